@@ -0,0 +1,70 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::num;
+use std::string;
+
+use gif;
+use image;
+
+/// The error type for this crate's I/O and parsing failures, returned instead of
+/// panicking so callers (the CLI, or anyone using this as a library) can recover.
+pub enum Error {
+    Io(io::Error),
+    Parse(String),
+    MissingMetadata(String),
+    MissingArg(String),
+    BadColourRamp(String),
+    Corrupt(String),
+    Image(image::ImageError),
+    Gif(gif::EncodingError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            &Error::Parse(ref msg) => write!(f, "couldn't parse value: {}", msg),
+            &Error::MissingMetadata(ref key) => write!(f, "missing required metadata key: {}", key),
+            &Error::MissingArg(ref name) => write!(f, "missing required argument: --{}", name),
+            &Error::BadColourRamp(ref msg) => write!(f, "invalid colour ramp: {}", msg),
+            &Error::Corrupt(ref msg) => write!(f, "intermediate file corrupt: {}", msg),
+            &Error::Image(ref e) => write!(f, "image error: {}", e),
+            &Error::Gif(ref e) => write!(f, "GIF encoding error: {}", e),
+        }
+    }
+}
+
+// Delegating Debug to Display means `main() -> Result<(), Error>` prints a clean
+// one-line diagnostic instead of a derived Debug dump.
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error { Error::Io(e) }
+}
+
+impl From<num::ParseIntError> for Error {
+    fn from(e: num::ParseIntError) -> Error { Error::Parse(e.to_string()) }
+}
+
+impl From<num::ParseFloatError> for Error {
+    fn from(e: num::ParseFloatError) -> Error { Error::Parse(e.to_string()) }
+}
+
+impl From<string::FromUtf8Error> for Error {
+    fn from(e: string::FromUtf8Error) -> Error { Error::Parse(e.to_string()) }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Error { Error::Image(e) }
+}
+
+impl From<gif::EncodingError> for Error {
+    fn from(e: gif::EncodingError) -> Error { Error::Gif(e) }
+}
@@ -0,0 +1,754 @@
+//! Core pipeline for turning OSM full-history PBF dumps into heatmap-style animations:
+//! `read_pbf`/`read_frames` produce a `Frames` table, `create_gif`/`create_frames` render it.
+
+extern crate osmio;
+extern crate image;
+extern crate gif;
+extern crate orthoproj;
+
+use std::fs;
+use osmio::OSMReader;
+use osmio::pbf::PBFReader;
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufRead, BufWriter};
+use std::collections::HashMap;
+use std::cmp::Ordering;
+
+use image::ImageBuffer;
+
+use gif::SetParameter;
+
+mod error;
+pub use error::Error;
+
+// (frame_no, (pixel_index, num_changes))
+pub type Frames = Vec<(u32, Vec<(u32, u16)>)>;
+
+pub type Metadata = HashMap<String, String>;
+
+// Magic bytes written at the start of a binary intermediate file, and sniffed on read
+// to autodetect the format.
+const INTERMEDIATE_MAGIC: [u8; 4] = *b"OHAB";
+const INTERMEDIATE_VERSION: u16 = 1;
+
+pub enum IntermediateFormat { Text, Binary }
+
+pub enum Projection { Ortho, Equirect }
+
+pub struct ColourRamp {
+    empty_colour: (u8, u8, u8),
+    steps: Vec<(u32, (u8, u8, u8))>,
+}
+
+impl ColourRamp {
+    pub fn new_from_filename(filename: &str) -> Result<Self, Error> {
+        let mut contents = String::new();
+        let mut file = fs::File::open(filename)?;
+        file.read_to_string(&mut contents)?;
+        Self::new_from_text(&contents)
+    }
+
+    pub fn new_from_text(source: &str) -> Result<Self, Error> {
+        let lines: Vec<_> = source.lines().collect();
+        if lines.is_empty() {
+            return Err(Error::BadColourRamp("file is empty".to_string()));
+        }
+
+        let empty_vec = lines[0].split(",").filter_map(|x| x.parse::<u8>().ok()).take(3).collect::<Vec<_>>();
+        if empty_vec.len() < 3 {
+            return Err(Error::BadColourRamp("first line must be the empty colour as 3 comma-separated bytes".to_string()));
+        }
+        let empty = (empty_vec[0], empty_vec[1], empty_vec[2]);
+
+        let mut steps = Vec::new();
+        for line in lines.iter().skip(1) {
+            let line = line.split(",").filter_map(|x| x.parse::<u32>().ok()).take(4).collect::<Vec<_>>();
+            if line.len() < 4 {
+                return Err(Error::BadColourRamp(format!("expected age,r,g,b, got {:?}", line)));
+            }
+            let age = line[0];
+            let colour = (line[1] as u8, line[2] as u8, line[3] as u8);
+            steps.push((age, colour));
+        }
+
+        if steps.len() > 254 {
+            return Err(Error::BadColourRamp(format!("{} steps given, maximum is 254", steps.len())));
+        }
+
+        Ok(ColourRamp{ empty_colour: empty, steps: steps })
+    }
+
+    pub fn palette(&self) -> Vec<u8> {
+        let mut results = Vec::with_capacity((self.steps.len()+1)*3);
+        results.push(self.empty_colour.0);
+        results.push(self.empty_colour.1);
+        results.push(self.empty_colour.2);
+
+        for &(_, (r, g, b)) in self.steps.iter() {
+            results.push(r);
+            results.push(g);
+            results.push(b);
+        }
+
+        results
+    }
+
+    pub fn index_for_magnitude(&self, magnitude: Option<u32>) -> u8 {
+        match magnitude {
+            None => 0,
+            Some(magnitude) => {
+                if magnitude > 255 {
+                    1
+                } else {
+                    (255 - magnitude) as u8
+                }
+            },
+        }
+    }
+
+    // Used by the true-colour RGBA rendering path, which isn't limited to 254 steps
+    // and so can blend smoothly between them instead of snapping to the nearest one.
+    pub fn colour_for_magnitude(&self, magnitude: Option<f32>) -> (u8, u8, u8) {
+        let magnitude = match magnitude {
+            None => return self.empty_colour,
+            Some(magnitude) => magnitude,
+        };
+
+        if self.steps.is_empty() {
+            return self.empty_colour;
+        }
+
+        let (first_age, first_colour) = self.steps[0];
+        if magnitude <= first_age as f32 {
+            return first_colour;
+        }
+
+        let (last_age, last_colour) = *self.steps.last().unwrap();
+        if magnitude >= last_age as f32 {
+            return last_colour;
+        }
+
+        for pair in self.steps.windows(2) {
+            let (age0, (r0, g0, b0)) = pair[0];
+            let (age1, (r1, g1, b1)) = pair[1];
+            if magnitude >= age0 as f32 && magnitude <= age1 as f32 {
+                let t = (magnitude - age0 as f32) / (age1 as f32 - age0 as f32);
+                let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+                return (lerp(r0, r1), lerp(g0, g1), lerp(b0, b1));
+            }
+        }
+
+        last_colour
+    }
+}
+
+pub fn read_pbf(filename: &str, sec_per_frame: u32, pixel_func: Box<Fn(f32, f32) -> Option<u32>>) -> Result<Frames, Error> {
+    let file = BufReader::new(fs::File::open(&filename)?);
+    let mut node_reader = PBFReader::new(file);
+    let node_reader = node_reader.nodes();
+
+    let mut results: HashMap<u32, HashMap<u32, u16>> = HashMap::new();
+
+    // 1st April 2005, midnight GMT. We presume no OSM editing before then.
+    let osm_epoch = 1109635200;
+
+    let mut first_frame_no = std::u32::MAX;
+    let mut last_frame_no = 0;
+
+    let mut num_nodes: u64 = 0;
+    for node in node_reader {
+        if let (Some(lat), Some(lon)) = (node.lat, node.lon) {
+
+            let timestamp = node.timestamp.to_epoch_number() as u64;
+            if timestamp < osm_epoch {
+                return Err(Error::Parse(format!("timestamp {} is before the OSM epoch", timestamp)));
+            }
+            let offset = (timestamp - osm_epoch) as u32;
+            // TODO double check that this rounds down
+            let frame_no = offset / sec_per_frame;
+
+            if frame_no < first_frame_no {
+                first_frame_no = frame_no;
+            }
+            if frame_no > last_frame_no {
+                last_frame_no = frame_no;
+            }
+
+            //let pixel_idx = latlon_to_pixel_index(lat, lon, width, height, &bbox);
+            if let Some(pixel_idx) = pixel_func(lat, lon) {
+                let curr_val = results.entry(frame_no).or_insert(HashMap::new()).entry(pixel_idx).or_insert(0);
+                if *curr_val < std::u16::MAX {
+                    *curr_val += 1;
+                }
+            }
+
+            num_nodes += 1;
+            if num_nodes % 50_000_000 == 0 {
+                println!("Read {} million points", num_nodes/1_000_000);
+            }
+        }
+    }
+    println!("Read {} points", num_nodes);
+    let num_frames = last_frame_no - first_frame_no + 1;
+    println!("There are {} frames, which is {} sec", num_frames, num_frames as f32/30.);
+
+    let mut sorted_results = Vec::with_capacity((last_frame_no-first_frame_no+1) as usize);
+
+    for frame_no in 0..num_frames {
+        match results.remove(&(frame_no+first_frame_no)) {
+            None => { sorted_results.push((frame_no, Vec::with_capacity(0))) },
+            Some(pixels) => {
+                let pixels = pixels.into_iter().collect();
+                sorted_results.push((frame_no, pixels));
+            }
+        }
+    }
+
+    Ok(sorted_results)
+}
+
+pub fn write_frames(frames: Frames, filename: &str, height: u32, width: u32, centre: &[f32; 2], sec_per_frame: u32, bbox: &[f32; 4], projection: &Projection, format: &IntermediateFormat) -> Result<(), Error> {
+    match format {
+        &IntermediateFormat::Text => write_frames_text(frames, filename, height, width, centre, sec_per_frame, bbox, projection),
+        &IntermediateFormat::Binary => write_frames_binary(frames, filename, height, width, centre, sec_per_frame, bbox, projection),
+    }
+}
+
+// CRC-32 (the same polynomial used by zip/gzip), computed over the whole intermediate
+// file body and stored as a trailing big-endian u32 so a truncated or corrupted
+// --save-intermediate run is caught with a clear error instead of an opaque parse panic.
+//
+// Both the write and verify sides stream instead of buffering the whole file in memory,
+// since intermediates for planet-scale runs can be many gigabytes.
+const CRC32_SEED: u32 = 0xFFFF_FFFF;
+const CRC32_CHUNK_SIZE: usize = 64 * 1024;
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for n in 0..256u32 {
+        let mut a = n;
+        for _ in 0..8 {
+            a = if a & 1 == 1 { 0xEDB8_8320 ^ (a >> 1) } else { a >> 1 };
+        }
+        table[n as usize] = a;
+    }
+    table
+}
+
+fn crc32_update(crc: u32, table: &[u32; 256], data: &[u8]) -> u32 {
+    let mut crc = crc;
+    for &byte in data {
+        crc = (crc >> 8) ^ table[((crc & 0xFF) ^ byte as u32) as usize];
+    }
+    crc
+}
+
+// Wraps a `Write` so the CRC is folded in as bytes go past, instead of re-reading
+// the file after it's been written.
+struct CrcWriter<W: Write> {
+    inner: W,
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl<W: Write> CrcWriter<W> {
+    fn new(inner: W) -> CrcWriter<W> {
+        CrcWriter { inner: inner, table: crc32_table(), crc: CRC32_SEED }
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.inner.write_all(&(!self.crc).to_be_bytes())?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32_update(self.crc, &self.table, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn verify_crc(filename: &str) -> Result<u64, Error> {
+    let file_len = fs::metadata(filename)?.len();
+    if file_len < 4 {
+        return Err(Error::Corrupt(format!("{} is too short to contain a checksum", filename)));
+    }
+    let body_len = file_len - 4;
+
+    let table = crc32_table();
+    let mut crc = CRC32_SEED;
+    let mut reader = BufReader::new(fs::File::open(&filename)?).take(body_len);
+    let mut buf = [0u8; CRC32_CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 { break; }
+        crc = crc32_update(crc, &table, &buf[..n]);
+    }
+    let actual_crc = !crc;
+
+    let mut stored_crc_bytes = [0u8; 4];
+    let mut trailer = fs::File::open(&filename)?;
+    trailer.seek(SeekFrom::Start(body_len))?;
+    trailer.read_exact(&mut stored_crc_bytes)?;
+    let stored_crc = u32::from_be_bytes(stored_crc_bytes);
+
+    if actual_crc != stored_crc {
+        return Err(Error::Corrupt(format!("{} failed its checksum (expected {:08x}, got {:08x})", filename, stored_crc, actual_crc)));
+    }
+
+    Ok(body_len)
+}
+
+fn write_frames_text(frames: Frames, filename: &str, height: u32, width: u32, centre: &[f32; 2], sec_per_frame: u32, bbox: &[f32; 4], projection: &Projection) -> Result<(), Error> {
+    let mut file = CrcWriter::new(BufWriter::new(fs::File::create(&filename)?));
+
+    writeln!(file, "metadata version {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(file, "metadata height {}", height)?;
+    writeln!(file, "metadata width {}", width)?;
+    writeln!(file, "metadata sec_per_frame {}", sec_per_frame)?;
+    writeln!(file, "metadata bbox {},{},{},{}", bbox[0], bbox[1], bbox[2], bbox[3])?;
+    writeln!(file, "metadata centre {},{}", centre[0], centre[1])?;
+    match projection {
+        &Projection::Ortho => {
+            writeln!(file, "metadata projection ortho")?;
+        },
+        &Projection::Equirect => {
+            writeln!(file, "metadata projection equirect")?;
+        },
+    }
+    writeln!(file, "")?;
+
+    for (frame_no, pixels) in frames.into_iter() {
+        write!(file, "{}", frame_no)?;
+        for p in pixels {
+            write!(file, ",{},{}", p.0, p.1)?;
+        }
+        write!(file, "\n")?;
+    }
+
+    file.finish()
+}
+
+fn metadata_pairs(height: u32, width: u32, centre: &[f32; 2], sec_per_frame: u32, bbox: &[f32; 4], projection: &Projection) -> Vec<(String, String)> {
+    let projection = match projection {
+        &Projection::Ortho => "ortho",
+        &Projection::Equirect => "equirect",
+    };
+
+    vec![
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("height".to_string(), height.to_string()),
+        ("width".to_string(), width.to_string()),
+        ("sec_per_frame".to_string(), sec_per_frame.to_string()),
+        ("bbox".to_string(), format!("{},{},{},{}", bbox[0], bbox[1], bbox[2], bbox[3])),
+        ("centre".to_string(), format!("{},{}", centre[0], centre[1])),
+        ("projection".to_string(), projection.to_string()),
+    ]
+}
+
+fn write_frames_binary(frames: Frames, filename: &str, height: u32, width: u32, centre: &[f32; 2], sec_per_frame: u32, bbox: &[f32; 4], projection: &Projection) -> Result<(), Error> {
+    let mut file = CrcWriter::new(BufWriter::new(fs::File::create(&filename)?));
+
+    file.write_all(&INTERMEDIATE_MAGIC)?;
+    file.write_all(&INTERMEDIATE_VERSION.to_be_bytes())?;
+
+    let metadata = metadata_pairs(height, width, centre, sec_per_frame, bbox, projection);
+    file.write_all(&(metadata.len() as u16).to_be_bytes())?;
+    for (key, value) in metadata {
+        file.write_all(&(key.len() as u16).to_be_bytes())?;
+        file.write_all(key.as_bytes())?;
+        file.write_all(&(value.len() as u16).to_be_bytes())?;
+        file.write_all(value.as_bytes())?;
+    }
+
+    for (frame_no, pixels) in frames.into_iter() {
+        file.write_all(&frame_no.to_be_bytes())?;
+        file.write_all(&(pixels.len() as u32).to_be_bytes())?;
+        for (pixel_index, num_changes) in pixels {
+            file.write_all(&pixel_index.to_be_bytes())?;
+            file.write_all(&num_changes.to_be_bytes())?;
+        }
+    }
+
+    file.finish()
+}
+
+fn is_binary_intermediate(filename: &str) -> Result<bool, Error> {
+    let mut file = fs::File::open(&filename)?;
+    let mut magic = [0u8; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == INTERMEDIATE_MAGIC),
+        Err(_) => Ok(false),
+    }
+}
+
+fn read_u16<R: Read>(file: &mut R) -> Result<u16, Error> {
+    let mut buf = [0u8; 2];
+    file.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(file: &mut R) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    file.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_binary_header<R: Read>(file: &mut R) -> Result<(Metadata, u64), Error> {
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+    let _version = read_u16(file)?;
+
+    let count = read_u16(file)?;
+    let mut bytes_read: u64 = 4 + 2 + 2;
+
+    let mut metadata = Metadata::new();
+    for _ in 0..count {
+        let key_len = read_u16(file)? as usize;
+        let mut key = vec![0u8; key_len];
+        file.read_exact(&mut key)?;
+        let value_len = read_u16(file)? as usize;
+        let mut value = vec![0u8; value_len];
+        file.read_exact(&mut value)?;
+
+        bytes_read += 2 + key_len as u64 + 2 + value_len as u64;
+        metadata.insert(String::from_utf8(key)?, String::from_utf8(value)?);
+    }
+
+    Ok((metadata, bytes_read))
+}
+
+fn read_metadata_binary(filename: &str) -> Result<Metadata, Error> {
+    let mut file = BufReader::new(fs::File::open(&filename)?);
+    let (metadata, _bytes_read) = read_binary_header(&mut file)?;
+    Ok(metadata)
+}
+
+fn read_frames_binary(filename: &str, body_len: u64) -> Result<Frames, Error> {
+    let mut file = BufReader::new(fs::File::open(&filename)?);
+
+    let (_metadata, mut bytes_read) = read_binary_header(&mut file)?;
+
+    let mut results = Frames::new();
+    while bytes_read < body_len {
+        let frame_no = read_u32(&mut file)?;
+        let pixel_count = read_u32(&mut file)?;
+        bytes_read += 8;
+
+        let mut pixels = Vec::with_capacity(pixel_count as usize);
+        for _ in 0..pixel_count {
+            let pixel_index = read_u32(&mut file)?;
+            let num_changes = read_u16(&mut file)?;
+            bytes_read += 6;
+            pixels.push((pixel_index, num_changes));
+        }
+        results.push((frame_no, pixels));
+    }
+
+    Ok(results)
+}
+
+pub fn read_metadata(filename: &str) -> Result<Metadata, Error> {
+    let body_len = verify_crc(filename)?;
+    if is_binary_intermediate(filename)? {
+        read_metadata_binary(filename)
+    } else {
+        read_metadata_text(filename, body_len)
+    }
+}
+
+fn read_metadata_text(filename: &str, body_len: u64) -> Result<Metadata, Error> {
+    let file = BufReader::new(fs::File::open(&filename)?.take(body_len));
+    let mut metadata = Metadata::new();
+
+    for line in file.lines().filter_map(|x| x.ok()).take_while(|x| x.len() > 0) {
+        let words: Vec<_> = line.split(" ").skip(1).take(2).collect();
+        match words.as_slice() {
+            &[key, value] => { metadata.insert(key.to_string(), value.to_string()); },
+            _ => return Err(Error::Parse(format!("malformed metadata line: {}", line))),
+        }
+    }
+
+    Ok(metadata)
+}
+
+pub fn read_frames(filename: &str) -> Result<Frames, Error> {
+    let body_len = verify_crc(filename)?;
+    if is_binary_intermediate(filename)? {
+        read_frames_binary(filename, body_len)
+    } else {
+        read_frames_text(filename, body_len)
+    }
+}
+
+fn read_frames_text(filename: &str, body_len: u64) -> Result<Frames, Error> {
+    let mut results = Frames::new();
+
+    let file = BufReader::new(fs::File::open(&filename)?.take(body_len));
+
+    for line in file.lines().filter_map(|x| x.ok()).skip_while(|x| x.starts_with("metadata ") || x.len() == 0) {
+        let mut parts = line.split(",");
+        let frame_no: u32 = parts.next().ok_or_else(|| Error::Parse("missing frame number".to_string()))?.parse()?;
+        let pixels: Vec<(u32, u16)> = parts.collect::<Vec<_>>().chunks(2)
+            .map(|pair| match pair {
+                &[a, b] => Ok((a.parse()?, b.parse()?)),
+                _ => Err(Error::Parse(format!("malformed pixel list on frame {}", frame_no))),
+            })
+            .collect::<Result<_, Error>>()?;
+        results.push((frame_no, pixels))
+    }
+
+    Ok(results)
+}
+
+pub fn latlon_to_pixel_index(lat: f32, lon: f32, width: u32, height: u32, bbox: &[f32; 4]) -> Option<u32> {
+    let left = bbox[0]; let bottom = bbox[1]; let right = bbox[2]; let top = bbox[3];
+    let bbox_width = right - left;
+    let bbox_height = top - bottom;
+
+    // FIXME should be able to do non-equals but it fails for point at south pole
+    if lat >= top || lat <= bottom || lon >= right || lon <= left {
+        return None;
+    }
+
+    let lat0 = top - lat;
+    let lon0 = lon - left;
+
+
+    let x = ((lon0/bbox_width)*(width as f32)) as u32;
+    let y = ((lat0/bbox_height)*(height as f32)) as u32;
+
+    let i = y * width + x;
+
+    assert!(i < width*height, "{} L{}, lat = {} lon = {} width = {} height = {} bbox = {:?} x = {} y = {} i = {}", file!(), line!(), lat, lon, width, height, bbox, x, y, i);
+
+    Some(i)
+}
+
+fn get_max_value(image: &Vec<Option<f32>>) -> f32 {
+    let mut max = 0.;
+    for pixel in image.iter().filter_map(|&x| x) {
+        max = match pixel.partial_cmp(&max) {
+            Some(Ordering::Greater) => pixel,
+            _ => max,
+        };
+    }
+
+    max
+}
+
+pub enum DecayMode { Exponential, Linear, None }
+
+// How brightness is scaled into the 0..255 range before it hits the colour ramp.
+// Fixed covers both an explicit --normalize fixed:<value> and a --normalize global
+// value precomputed once up front by `compute_global_max`.
+pub enum Normalize { PerFrame, Fixed(f32) }
+
+fn decay_image(image: &mut Vec<Option<f32>>, decay_mode: &DecayMode, decay_factor: f32) {
+    match decay_mode {
+        &DecayMode::None => {},
+        &DecayMode::Exponential => {
+            for i in 0..image.len() {
+                if image[i].is_some() && image[i].unwrap() > 0. {
+                    image[i] = image[i].map(|x| x*decay_factor);
+                }
+            }
+        },
+        &DecayMode::Linear => {
+            for i in 0..image.len() {
+                if image[i].is_some() && image[i].unwrap() > 0. {
+                    image[i] = image[i].map(|x| (x - decay_factor).max(0.));
+                }
+            }
+        },
+    }
+}
+
+fn normalized_max(image: &Vec<Option<f32>>, normalize: &Normalize) -> f32 {
+    match normalize {
+        &Normalize::PerFrame => get_max_value(image),
+        &Normalize::Fixed(value) => value,
+    }
+}
+
+// Used by --normalize global: replays the whole accumulate/decay sequence once up
+// front so brightness can be scaled against the animation's overall peak instead of
+// flickering as each frame's own maximum jumps around.
+pub fn compute_global_max(frames: &Frames, height: u32, width: u32, decay_mode: &DecayMode, decay_factor: f32) -> f32 {
+    let mut image = vec![None; (width*height) as usize];
+    let mut max = 0f32;
+
+    for &(_, ref pixels) in frames.iter() {
+        decay_image(&mut image, decay_mode, decay_factor);
+
+        for &(i, magnitude) in pixels.iter() {
+            if i < width*height {
+                let new_value = image[i as usize].unwrap_or(0f32) + (magnitude as f32);
+                image[i as usize] = Some(new_value);
+            }
+        }
+
+        let frame_max = get_max_value(&image);
+        if frame_max > max {
+            max = frame_max;
+        }
+    }
+
+    max
+}
+
+
+pub fn create_gif(frames: Frames, output_image_filename: &str, height: u32, width: u32, colour_ramp: &ColourRamp, decay_mode: &DecayMode, decay_factor: f32, normalize: &Normalize) -> Result<(), Error> {
+    // The indexed path shares one global palette, so it can only hold 254 ramp steps.
+    if colour_ramp.steps.len() > 254 {
+        return Err(Error::BadColourRamp(format!("{} steps given, maximum is 254 without --truecolor", colour_ramp.steps.len())));
+    }
+
+    let mut output_file = fs::File::create(output_image_filename)?;
+
+    // FIXME change width/height to u16?
+    let mut encoder = gif::Encoder::new(&mut output_file, width as u16, height as u16, &colour_ramp.palette())?;
+    encoder.set(gif::Repeat::Infinite)?;
+
+    let mut image = vec![None; (width*height) as usize];
+
+    for (frame_no, pixels) in frames.into_iter() {
+
+        decay_image(&mut image, decay_mode, decay_factor);
+
+        for (i, magnitude) in pixels {
+            // FIXME sometimes the value is invalid
+            //assert!(i < width*height, "{} L{}, width = {} height = {} i = {}", file!(), line!(), width, height, i);
+            if i < width*height {
+                let new_value= image[i as usize].unwrap_or(0f32) + (magnitude as f32);
+                image[i as usize] = Some(new_value);
+            }
+        }
+
+        let max = normalized_max(&image, normalize);
+
+        let mut pixels: Vec<u8> = Vec::with_capacity(image.len() * 4);
+        for p in image.iter().cloned() {
+            pixels.push(match p {
+                None => 0,
+                Some(x) => { (255f32 * (x/max)).round() as u8 },
+            });
+        }
+
+        let mut frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, pixels.as_mut_slice(), None);
+        // 30 fps, and delay is in units of 10ms.
+        frame.delay = 100 / 30;
+
+        encoder.write_frame(&frame)?;
+
+        if frame_no % 30 == 0 {
+            println!("Wrote frame {}", frame_no);
+        }
+
+    }
+
+    Ok(())
+}
+
+// Full 24-bit colour per frame, at the cost of a local palette having to be quantized
+// for every frame rather than reusing one global indexed palette. Unlike create_gif,
+// this isn't capped at 254 colour ramp steps and blends smoothly between them.
+pub fn create_gif_truecolor(frames: Frames, output_image_filename: &str, height: u32, width: u32, colour_ramp: &ColourRamp, decay_mode: &DecayMode, decay_factor: f32, normalize: &Normalize) -> Result<(), Error> {
+    let mut output_file = fs::File::create(output_image_filename)?;
+
+    let mut encoder = gif::Encoder::new(&mut output_file, width as u16, height as u16, &[])?;
+    encoder.set(gif::Repeat::Infinite)?;
+
+    let mut image = vec![None; (width*height) as usize];
+
+    for (frame_no, pixels) in frames.into_iter() {
+
+        decay_image(&mut image, decay_mode, decay_factor);
+
+        for (i, magnitude) in pixels {
+            if i < width*height {
+                let new_value = image[i as usize].unwrap_or(0f32) + (magnitude as f32);
+                image[i as usize] = Some(new_value);
+            }
+        }
+
+        let max = normalized_max(&image, normalize);
+
+        let mut rgba: Vec<u8> = Vec::with_capacity(image.len() * 4);
+        for p in image.iter().cloned() {
+            let (r, g, b) = colour_ramp.colour_for_magnitude(p.map(|x| 255f32 * (x/max)));
+            rgba.push(r);
+            rgba.push(g);
+            rgba.push(b);
+            rgba.push(255);
+        }
+
+        let mut frame = gif::Frame::from_rgba(width as u16, height as u16, rgba.as_mut_slice());
+        frame.delay = 100 / 30;
+
+        encoder.write_frame(&frame)?;
+
+        if frame_no % 30 == 0 {
+            println!("Wrote frame {}", frame_no);
+        }
+
+    }
+
+    Ok(())
+}
+
+pub fn create_frames(frames: Frames, output_image_filename: &str, height: u32, width: u32, decay_mode: &DecayMode, decay_factor: f32, normalize: &Normalize) -> Result<(), Error> {
+
+    let mut image = vec![None; (width*height) as usize];
+
+    for (frame_no, pixels) in frames.into_iter() {
+
+        decay_image(&mut image, decay_mode, decay_factor);
+
+        for (i, magnitude) in pixels {
+            // FIXME sometimes the value is invalid
+            //assert!(i < width*height, "{} L{}, width = {} height = {} i = {}", file!(), line!(), width, height, i);
+            if i < width*height {
+                let new_value= image[i as usize].unwrap_or(0f32) + (magnitude as f32);
+                image[i as usize] = Some(new_value);
+            }
+        }
+
+        let max = normalized_max(&image, normalize);
+
+        let img = ImageBuffer::from_fn(width, height, |x, y| {
+            let p = (y*width + x) as usize;
+            let red = match image[p] { None => 0, Some(val) => { (255f32 * (val/max)).round() as u8 }, };
+            image::Rgb([red, red, red])
+        });
+
+
+        let mut pixels: Vec<u8> = Vec::with_capacity(image.len() * 4);
+        for p in image.iter().cloned() {
+            pixels.push(match p {
+                None => 0,
+                Some(x) => { (255f32 * (x/max)).round() as u8 },
+            });
+        }
+
+
+        let ref mut fout = fs::File::create(&format!("{}{:06}.png", output_image_filename, frame_no))?;
+        image::ImageRgb8(img).save(fout, image::PNG)?;
+
+
+        if frame_no % 30 == 0 {
+            println!("Wrote frame {}", frame_no);
+        }
+
+    }
+    println!("Finished. You can convert this to a video with this command:\n\navconv -framerate 30  -i {}%06d.png output.mp4\n\n", output_image_filename);
+
+    Ok(())
+}